@@ -3,6 +3,7 @@ use aes_gcm::Aes256Gcm;
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::management_canister::vetkd::*;
 use ic_cdk_macros::{query, update};
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::collections::HashMap;
 
@@ -13,6 +14,7 @@ use std::collections::HashMap;
 type ModelUpdate = Vec<u8>;
 type GlobalModel = Vec<u8>;
 type ClientId = u64;
+type UploadId = u64;
 
 // ==================================================================================================
 // State
@@ -22,6 +24,8 @@ type ClientId = u64;
 pub enum AggregationMode {
     Plain,
     SMPC,
+    FHE,
+    SecureAgg,
 }
 
 impl Default for AggregationMode {
@@ -33,6 +37,78 @@ impl Default for AggregationMode {
 // Fixed-point scaling factor used for SMPC integer encoding of gradients
 const SMPC_SCALE: i64 = 1_000_000; // 1e6
 
+// A single additive Shamir (t-of-n) share: the evaluation point x and the
+// polynomial value y = f(x) over the Shamir prime field.
+//
+// The dealt secret (a self-mask seed b_i or pairwise seed seed_ij) must be
+// chosen off-chain as a value in [0, SHAMIR_PRIME), i.e. already reduced mod
+// SHAMIR_PRIME before it's used both as the polynomial's constant term here
+// and as the PRG seed the client mixes into y_i. shamir_reconstruct always
+// returns a value in that same range; if the client instead seeded its mask
+// with a value >= SHAMIR_PRIME, the on-chain reconstruction would recover a
+// different residue and prg_mask would fail to reproduce the client's mask.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct ShamirShare {
+    x: u64,
+    y: i128,
+}
+
+// Shared prime field modulus (2^61 - 1, a Mersenne prime) used by the
+// reference field arithmetic in this crate: Shamir secret sharing of mask
+// seeds, and Pedersen commitments below.
+const SHAMIR_PRIME: i128 = 2_305_843_009_213_693_951;
+
+// Toy Pedersen generators over the SHAMIR_PRIME field. A production
+// deployment would use Ristretto group generators (curve25519-dalek); this
+// crate vendors no elliptic-curve dependency, so commitments here are a
+// structural stand-in: additively homomorphic, but computed linearly as
+// v*G + r*H mod SHAMIR_PRIME, so they are not binding: for any other v a
+// matching r that opens the same commitment can be solved for directly.
+const PEDERSEN_G: i128 = 5;
+const PEDERSEN_H: i128 = 7;
+
+// Bit width of the agreed value range [0, 2^n) that bounded updates are
+// checked to lie within, after re-centering signed coordinates by 2^(n-1).
+const RANGE_PROOF_BIT_WIDTH: u32 = 32;
+
+// Append-only record of one completed aggregation cycle, chaining in the
+// previous cycle's transcript hash so tampering or silent exclusion of an
+// honest client's update is detectable by an external verifier walking the
+// chain.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CycleTranscript {
+    cycle: u64,
+    participants: Vec<ClientId>,
+    decrypted_updates_count: u64,
+    num_s: u64,
+    dropped_clients: Vec<ClientId>,
+    global_model_hash: [u8; 32],
+    aggregation_mode: AggregationMode,
+    transcript_hash: [u8; 32],
+}
+
+// Staging area for an in-progress chunked model-update upload.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingUpload {
+    client_id: ClientId,
+    cycle: u64,
+    total_len: u64,
+    part_count: u32,
+    parts: HashMap<u32, Vec<u8>>,
+    staged_bytes: u64,
+}
+
+// Upper bound on a single FHE ciphertext's wire-encoded size, and on the
+// total ciphertext bytes accepted for one cycle, to keep canister heap usage
+// bounded.
+const MAX_FHE_CIPHERTEXT_BYTES: usize = 1 << 20; // 1 MiB per ciphertext
+const MAX_FHE_CYCLE_BYTES: usize = 64 << 20; // 64 MiB per cycle
+
+// Upper bound on a declared multipart model-update upload's total size. The
+// bytes staged for an upload are in turn capped at its declared total_len by
+// upload_part, so one upload can't stage more than this bound.
+const MAX_UPLOAD_TOTAL_BYTES: u64 = 64 << 20; // 64 MiB per upload
+
 #[derive(CandidType, Deserialize, Default)]
 pub struct State {
     global_model: GlobalModel,
@@ -46,6 +122,37 @@ pub struct State {
     smpc_t_sums: HashMap<u64, HashMap<ClientId, Vec<i64>>>,
     // Snapshot of participant client IDs per cycle (for off-chain pairwise masking)
     cycle_participants: HashMap<u64, Vec<ClientId>>,
+    // Secure aggregation (dropout-resilient): masked vectors y_i per cycle
+    secure_masked_vectors: HashMap<u64, HashMap<ClientId, Vec<i64>>>,
+    // Shamir shares of each client's self-mask seed b_i, addressed to a holder client
+    secure_self_mask_shares: HashMap<u64, HashMap<ClientId, HashMap<ClientId, ShamirShare>>>,
+    // Shamir shares of the pairwise PRG seed for an (i, j) pair, keyed by the
+    // normalized pair, then by the dealer (the client who computed seed_ij and
+    // split it), then by the holder the share was addressed to. seed_ij is
+    // symmetric, so both i and j deal shares of the *same* seed value — without
+    // the dealer layer, two dealers' shares landing in the same holder slot
+    // would let shamir_reconstruct interpolate a mix of two unrelated
+    // polynomials and silently recover the wrong seed.
+    secure_pairwise_mask_shares: HashMap<u64, HashMap<(ClientId, ClientId), HashMap<ClientId, HashMap<ClientId, ShamirShare>>>>,
+    // FHE aggregation: per-client distributed key-gen shares for the cycle's common public key
+    fhe_key_shares: HashMap<u64, HashMap<ClientId, Vec<u8>>>,
+    // FHE aggregation: per-client encrypted gradient ciphertexts for the cycle
+    fhe_ciphertexts: HashMap<u64, HashMap<ClientId, Vec<u8>>>,
+    // FHE aggregation: homomorphic coordinate-wise sum of the cycle's ciphertexts, once computed
+    fhe_aggregate_ciphertext: HashMap<u64, Vec<u8>>,
+    // FHE aggregation: the clients whose ciphertexts were actually folded into
+    // fhe_aggregate_ciphertext (excludes any submitted with a mismatched vector length)
+    fhe_aggregate_contributors: HashMap<u64, Vec<ClientId>>,
+    // FHE aggregation: per-client threshold decryption shares for the cycle's aggregate ciphertext
+    fhe_decryption_shares: HashMap<u64, HashMap<ClientId, Vec<u8>>>,
+    // Pedersen commitments to each coordinate of a client's bounded update, per cycle
+    bounded_update_commitments: HashMap<u64, HashMap<ClientId, Vec<[u8; 32]>>>,
+    // Chunked multipart model-update uploads in progress
+    next_upload_id: UploadId,
+    pending_uploads: HashMap<UploadId, PendingUpload>,
+    // Audit trail: one transcript per completed aggregation cycle, hash-chained
+    cycle_transcripts: HashMap<u64, CycleTranscript>,
+    last_transcript_hash: [u8; 32],
 }
 
 thread_local! {
@@ -144,7 +251,19 @@ async fn run_aggregation() {
         }
 
         STATE.with_borrow_mut(|state| {
-            state.global_model = serde_json::to_vec(&aggregated_model).expect("Failed to serialize global model");
+            let global_model = serde_json::to_vec(&aggregated_model).expect("Failed to serialize global model");
+            let participants: Vec<ClientId> = cycle_updates.keys().cloned().collect();
+            record_cycle_transcript(
+                state,
+                current_cycle,
+                participants,
+                decrypted_updates_count as u64,
+                0,
+                Vec::new(),
+                &global_model,
+                AggregationMode::Plain,
+            );
+            state.global_model = global_model;
             state.model_updates.remove(&current_cycle);
         });
     }
@@ -162,6 +281,117 @@ fn start_new_cycle() -> u64 {
     })
 }
 
+// ==================================================================================================
+// Chunked Multipart Model-Update Upload
+// ==================================================================================================
+//
+// A whole model/ciphertext update can exceed the IC per-ingress-message size
+// limit, and buffering it in one shot bloats canister heap. This mirrors an
+// S3-multipart-style flow: the client declares the total size and part
+// count, uploads parts independently (each checksummed on arrival), and only
+// commits the assembled blob into model_updates once every part is present
+// and the whole-blob digest matches.
+
+#[update]
+fn begin_update_upload(total_len: u64, part_count: u32) -> UploadId {
+    assert!(part_count > 0, "part_count must be positive");
+    assert!(total_len <= MAX_UPLOAD_TOTAL_BYTES, "total_len exceeds the per-upload size bound");
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let upload_id = state.next_upload_id;
+        state.next_upload_id += 1;
+        state.pending_uploads.insert(
+            upload_id,
+            PendingUpload {
+                client_id,
+                cycle: state.current_cycle,
+                total_len,
+                part_count,
+                parts: HashMap::new(),
+                staged_bytes: 0,
+            },
+        );
+        upload_id
+    })
+}
+
+#[update]
+fn upload_part(upload_id: UploadId, part_index: u32, bytes: Vec<u8>, sha256: [u8; 32]) {
+    let digest: [u8; 32] = Sha256::digest(&bytes).into();
+    assert_eq!(digest, sha256, "Part checksum mismatch");
+
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let upload = state.pending_uploads.get_mut(&upload_id).expect("Unknown upload_id");
+        assert_eq!(upload.client_id, client_id, "Upload does not belong to caller");
+        assert!(part_index < upload.part_count, "part_index out of range");
+
+        let replaced_len = upload.parts.get(&part_index).map(|p| p.len() as u64).unwrap_or(0);
+        let staged_after = upload.staged_bytes - replaced_len + bytes.len() as u64;
+        assert!(staged_after <= upload.total_len, "Part would push staged bytes past the declared total_len");
+
+        upload.staged_bytes = staged_after;
+        upload.parts.insert(part_index, bytes);
+    });
+}
+
+#[update]
+fn complete_update_upload(upload_id: UploadId, full_sha256: [u8; 32]) {
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let upload = state.pending_uploads.get(&upload_id).expect("Unknown upload_id");
+        assert_eq!(upload.client_id, client_id, "Upload does not belong to caller");
+
+        let mut assembled = Vec::with_capacity(upload.total_len as usize);
+        for part_index in 0..upload.part_count {
+            let part = upload.parts.get(&part_index).unwrap_or_else(|| panic!("Missing part {part_index}"));
+            assembled.extend_from_slice(part);
+        }
+        assert_eq!(assembled.len() as u64, upload.total_len, "Assembled length does not match total_len");
+
+        let digest: [u8; 32] = Sha256::digest(&assembled).into();
+        assert_eq!(digest, full_sha256, "Assembled blob checksum mismatch");
+
+        let cycle = upload.cycle;
+        state
+            .model_updates
+            .entry(cycle)
+            .or_default()
+            .insert(client_id, assembled);
+        state.pending_uploads.remove(&upload_id);
+    });
+}
+
+#[update]
+fn abort_update_upload(upload_id: UploadId) {
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let upload = state.pending_uploads.get(&upload_id).expect("Unknown upload_id");
+        assert_eq!(upload.client_id, client_id, "Upload does not belong to caller");
+        state.pending_uploads.remove(&upload_id);
+    });
+}
+
 // ==================================================================================================
 // sMPC (Secure Multiparty Computation) Aggregation API
 // ==================================================================================================
@@ -171,6 +401,8 @@ fn get_aggregation_mode() -> String {
     STATE.with_borrow(|state| match state.aggregation_mode {
         AggregationMode::Plain => "PLAIN".to_string(),
         AggregationMode::SMPC => "SMPC".to_string(),
+        AggregationMode::FHE => "FHE".to_string(),
+        AggregationMode::SecureAgg => "SECURE_AGG".to_string(),
     })
 }
 
@@ -179,6 +411,8 @@ fn set_aggregation_mode(mode: String) {
     STATE.with_borrow_mut(|state| {
         state.aggregation_mode = match mode.to_ascii_uppercase().as_str() {
             "SMPC" => AggregationMode::SMPC,
+            "FHE" => AggregationMode::FHE,
+            "SECURE_AGG" => AggregationMode::SecureAgg,
             _ => AggregationMode::Plain,
         }
     })
@@ -233,11 +467,12 @@ fn upload_mask_sum_t(sum: Vec<i64>) {
 
 #[update]
 fn run_smpc_aggregation() {
-    let (cycle, s_map, t_map) = STATE.with_borrow(|state| {
+    let (cycle, s_map, t_map, expected_participants) = STATE.with_borrow(|state| {
         (
             state.current_cycle,
             state.smpc_s_shares.get(&state.current_cycle).cloned().unwrap_or_default(),
             state.smpc_t_sums.get(&state.current_cycle).cloned().unwrap_or_default(),
+            state.cycle_participants.get(&state.current_cycle).cloned().unwrap_or_default(),
         )
     });
 
@@ -283,13 +518,706 @@ fn run_smpc_aggregation() {
         aggregated_avg[i] = avg;
     }
 
+    let dropped_clients: Vec<ClientId> = expected_participants
+        .iter()
+        .filter(|id| !s_map.contains_key(id))
+        .cloned()
+        .collect();
+
     STATE.with_borrow_mut(|state| {
-        state.global_model = serde_json::to_vec(&aggregated_avg).expect("Failed to serialize global model");
+        let global_model = serde_json::to_vec(&aggregated_avg).expect("Failed to serialize global model");
+        let participants: Vec<ClientId> = s_map.keys().cloned().collect();
+        record_cycle_transcript(
+            state,
+            cycle,
+            participants,
+            0,
+            num_s as u64,
+            dropped_clients,
+            &global_model,
+            AggregationMode::SMPC,
+        );
+        state.global_model = global_model;
         state.smpc_s_shares.remove(&cycle);
         state.smpc_t_sums.remove(&cycle);
     });
 }
 
+// ==================================================================================================
+// Gradient Bounding (Pedersen commitments + aggregate range proof)
+// ==================================================================================================
+//
+// Clients submitting to upload_masked_update_s can currently contribute an
+// arbitrarily large coordinate with no detection. This layer adds Byzantine-
+// robust norm clipping: each quantized coordinate v_k is committed to as
+// C_k = v_k*G + r_k*H, and the share is only accepted into smpc_s_shares once
+// every coordinate is (a) shown to open the matching commitment and (b)
+// directly checked to lie in [-B, B) with B = 2^(n-1).
+//
+// Note: a real deployment would use Ristretto group commitments plus an
+// aggregated Bulletproof, verified without the canister ever seeing v_k or
+// r_k — that needs curve25519-dalek + the bulletproofs crate, neither of
+// which is vendored in this tree. upload_masked_update_s already stores s_i
+// in the clear, so this reference implementation doesn't regress any hiding
+// property by also requiring r_k in the clear: it trades the zero-knowledge
+// proof for a real, checkable opening of each commitment plus a direct bound
+// check, which is what actually enforces the norm clip.
+// has_expected_range_proof_shape is kept as a secondary, purely cosmetic
+// check that `proof`'s byte length matches what a real aggregated
+// Bulletproof for this many commitments would look like — it does not
+// verify the proof's contents and provides no soundness of its own.
+
+// Off-chain helper exposed for clients building a Pedersen commitment and
+// its accompanying range proof before calling upload_bounded_update.
+pub fn pedersen_commit(v: i64, r: i128) -> i128 {
+    ((v as i128) * PEDERSEN_G + r * PEDERSEN_H).rem_euclid(SHAMIR_PRIME)
+}
+
+fn encode_commitment(c: i128) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    bytes[16..32].copy_from_slice(&c.to_be_bytes());
+    bytes
+}
+
+fn decode_commitment(bytes: &[u8; 32]) -> i128 {
+    i128::from_be_bytes(bytes[16..32].try_into().expect("slice is 16 bytes"))
+}
+
+// Checks the proof has the byte shape of a valid aggregated Bulletproof for
+// `commitments.len()` commitments, each attesting a `bit_width`-bit range: an
+// aggregated range proof over `m` commitments of `bit_width`-bit ranges is
+// 2*ceil(log2(bit_width * m)) + 9 compressed points of 32 bytes apiece — the
+// proof does grow (logarithmically) with the number of aggregated
+// commitments. This is a byte-length check only and verifies nothing about
+// `proof`'s contents or its binding to `commitments` — any buffer of the
+// right length passes. It catches a client that didn't even try to shape a
+// proof; the real soundness for the range/norm clip below comes entirely
+// from the cleartext commitment-opening and bound checks in
+// upload_bounded_update, not from this function.
+fn has_expected_range_proof_shape(commitments: &[[u8; 32]], proof: &[u8], bit_width: u32) -> bool {
+    if commitments.is_empty() {
+        return false;
+    }
+    let n = (bit_width as u64) * (commitments.len() as u64);
+    let ceil_log2_n = if n.is_power_of_two() { n.ilog2() } else { n.ilog2() + 1 };
+    let expected_points = 2 * ceil_log2_n as usize + 9;
+    proof.len() == expected_points * 32
+}
+
+// The range/norm-clip guarantee for this endpoint comes solely from the
+// cleartext commitment-opening and [-B, B) bound checks below, not from
+// `proof`: has_expected_range_proof_shape only checks proof's byte length,
+// so it rejects a client that sent no proof at all but accepts any buffer of
+// the right size regardless of content.
+#[update]
+fn upload_bounded_update(
+    shares: Vec<i64>,
+    commitments: Vec<[u8; 32]>,
+    blinding_factors: Vec<i128>,
+    proof: Vec<u8>,
+) {
+    assert_eq!(shares.len(), commitments.len(), "One commitment is required per coordinate");
+    assert_eq!(shares.len(), blinding_factors.len(), "One blinding factor is required per coordinate");
+    assert!(
+        has_expected_range_proof_shape(&commitments, &proof, RANGE_PROOF_BIT_WIDTH),
+        "Range proof has the wrong shape for this many commitments"
+    );
+
+    let bound: i64 = 1i64 << (RANGE_PROOF_BIT_WIDTH - 1);
+    for (i, (&v, &r)) in shares.iter().zip(blinding_factors.iter()).enumerate() {
+        assert!((-bound..bound).contains(&v), "Coordinate {i} is out of the agreed [-B, B) range");
+        assert_eq!(
+            commitments[i],
+            encode_commitment(pedersen_commit(v, r)),
+            "Commitment {i} does not open to the submitted share and blinding factor"
+        );
+    }
+
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let cycle = state.current_cycle;
+        state.smpc_s_shares.entry(cycle).or_default().insert(client_id, shares);
+        state
+            .bounded_update_commitments
+            .entry(cycle)
+            .or_default()
+            .insert(client_id, commitments);
+    });
+}
+
+#[query]
+fn get_commitment_sum(cycle: u64) -> [u8; 32] {
+    STATE.with_borrow(|state| {
+        let mut sum: i128 = 0;
+        if let Some(per_client) = state.bounded_update_commitments.get(&cycle) {
+            for commitments in per_client.values() {
+                for c in commitments {
+                    sum = (sum + decode_commitment(c)).rem_euclid(SHAMIR_PRIME);
+                }
+            }
+        }
+        encode_commitment(sum)
+    })
+}
+
+// ==================================================================================================
+// Secure Aggregation (Bonawitz-style, dropout-resilient)
+// ==================================================================================================
+//
+// Unlike the sMPC path above, which silently produces a garbage aggregate if a
+// client vanishes mid-cycle, this path lets a client's contribution be cleanly
+// removed after the fact. Each client i derives (off-chain) a self-mask seed
+// b_i and a pairwise PRG seed seed_ij with every other participant j, then
+// uploads a masked vector y_i = quantize(x_i) + PRG(b_i) + sum_{j>i} PRG(seed_ij)
+// - sum_{j<i} PRG(seed_ij). Pairwise terms cancel exactly when both i and j
+// survive. If a client drops, the canister instead reconstructs its pairwise
+// seeds (from the Shamir shares dealt by either member of each pair) and
+// subtracts the residual terms those seeds left behind in the survivors' vectors.
+
+fn secure_agg_threshold(num_participants: usize) -> u64 {
+    (num_participants as u64) / 2 + 1
+}
+
+fn mask_pair_key(a: ClientId, b: ClientId) -> (ClientId, ClientId) {
+    if a <= b { (a, b) } else { (b, a) }
+}
+
+// Expand a reconstructed seed into a fixed-point mask vector of length `len`,
+// using a splitmix64-style PRG (no external RNG crate is vendored here).
+// `seed` must already be reduced into the Shamir field (0 <= seed <
+// SHAMIR_PRIME, see ShamirShare's doc comment) so that this reproduces
+// exactly the mask the client generated off-chain from the same seed.
+fn prg_mask(seed: i128, len: usize) -> Vec<i64> {
+    debug_assert!((0..SHAMIR_PRIME).contains(&seed), "seed must be reduced mod SHAMIR_PRIME");
+    let mut state = seed as u64;
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        out.push((z as i64) % SMPC_SCALE);
+    }
+    out
+}
+
+fn mod_inverse(a: i128, p: i128) -> i128 {
+    // Fermat's little theorem: a^(p-2) mod p, since p is prime.
+    let mut base = a.rem_euclid(p);
+    let mut exp = p - 2;
+    let mut result: i128 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % p;
+        }
+        base = base * base % p;
+        exp >>= 1;
+    }
+    result
+}
+
+// Lagrange-interpolate the shared secret (the polynomial's value at x = 0)
+// from at least `t` distinct shares.
+fn shamir_reconstruct(shares: &[ShamirShare]) -> i128 {
+    let p = SHAMIR_PRIME;
+    let mut secret: i128 = 0;
+    for (idx, share_i) in shares.iter().enumerate() {
+        let mut numerator: i128 = 1;
+        let mut denominator: i128 = 1;
+        for (jdx, share_j) in shares.iter().enumerate() {
+            if idx == jdx {
+                continue;
+            }
+            numerator = numerator * (-(share_j.x as i128)).rem_euclid(p) % p;
+            denominator = denominator * ((share_i.x as i128) - (share_j.x as i128)).rem_euclid(p) % p;
+        }
+        let lagrange_term = numerator * mod_inverse(denominator, p) % p;
+        secret = (secret + share_i.y * lagrange_term) % p;
+    }
+    secret.rem_euclid(p)
+}
+
+// Shares must deal a secret already reduced mod SHAMIR_PRIME (see
+// ShamirShare's doc comment) — the seed a client feeds to its own PRG when
+// building y_i must match what shamir_reconstruct later recovers on-chain.
+#[update]
+fn submit_secure_shares(
+    self_mask_shares: Vec<(ClientId, ShamirShare)>,
+    pairwise_mask_shares: Vec<(ClientId, ClientId, ShamirShare)>,
+) {
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let cycle = state.current_cycle;
+
+        // (holder, share of the caller's own self-mask seed b_i)
+        let self_shares_entry = state
+            .secure_self_mask_shares
+            .entry(cycle)
+            .or_default()
+            .entry(client_id)
+            .or_default();
+        for (holder, share) in self_mask_shares {
+            self_shares_entry.insert(holder, share);
+        }
+
+        // (peer, holder, share of the pairwise seed_{caller,peer}), addressed
+        // to an arbitrary holder so that a t-of-n threshold of *other*
+        // participants — not just the pair's own two members — can later
+        // reconstruct the seed if either caller or peer drops. Filed under
+        // the caller as dealer: seed_ij is symmetric, so both members of the
+        // pair deal shares of the same seed, and mixing their polynomials
+        // together would reconstruct garbage.
+        for (peer, holder, share) in pairwise_mask_shares {
+            let dealer_entry = state
+                .secure_pairwise_mask_shares
+                .entry(cycle)
+                .or_default()
+                .entry(mask_pair_key(client_id, peer))
+                .or_default()
+                .entry(client_id)
+                .or_default();
+            dealer_entry.insert(holder, share);
+        }
+    });
+}
+
+#[update]
+fn submit_masked_vector(vector: Vec<i64>) {
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let cycle = state.current_cycle;
+        state
+            .secure_masked_vectors
+            .entry(cycle)
+            .or_default()
+            .insert(client_id, vector);
+    });
+}
+
+#[query]
+fn get_recoverable_clients(cycle: u64) -> Vec<ClientId> {
+    STATE.with_borrow(|state| {
+        let participants = state.cycle_participants.get(&cycle).cloned().unwrap_or_default();
+        let threshold = secure_agg_threshold(participants.len());
+        let pairwise = state.secure_pairwise_mask_shares.get(&cycle);
+
+        participants
+            .into_iter()
+            .filter(|&client| {
+                let Some(pairwise) = pairwise else { return false };
+                participants_peers(&state.cycle_participants, cycle, client)
+                    .iter()
+                    .all(|&peer| {
+                        pairwise
+                            .get(&mask_pair_key(client, peer))
+                            .map(|dealers| dealers.values().any(|shares| shares.len() as u64 >= threshold))
+                            .unwrap_or(false)
+                    })
+            })
+            .collect()
+    })
+}
+
+fn participants_peers(
+    cycle_participants: &HashMap<u64, Vec<ClientId>>,
+    cycle: u64,
+    client: ClientId,
+) -> Vec<ClientId> {
+    cycle_participants
+        .get(&cycle)
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&peer| peer != client)
+        .collect()
+}
+
+#[update]
+fn run_secure_aggregation(dropped: Vec<ClientId>) {
+    let (cycle, participants, masked_vectors, self_mask_shares, pairwise_mask_shares) =
+        STATE.with_borrow(|state| {
+            let cycle = state.current_cycle;
+            (
+                cycle,
+                state.cycle_participants.get(&cycle).cloned().unwrap_or_default(),
+                state.secure_masked_vectors.get(&cycle).cloned().unwrap_or_default(),
+                state.secure_self_mask_shares.get(&cycle).cloned().unwrap_or_default(),
+                state.secure_pairwise_mask_shares.get(&cycle).cloned().unwrap_or_default(),
+            )
+        });
+
+    if masked_vectors.is_empty() {
+        return; // No updates for the current cycle
+    }
+
+    let threshold = secure_agg_threshold(participants.len());
+    let vec_len = masked_vectors.values().next().map(|v| v.len()).unwrap_or(0);
+    if vec_len == 0 {
+        return;
+    }
+
+    let mut sum_y = vec![0i64; vec_len];
+    let mut num_survivors = 0usize;
+    for (client, vector) in masked_vectors.iter() {
+        if dropped.contains(client) || vector.len() != vec_len {
+            continue;
+        }
+        for (i, val) in vector.iter().enumerate() {
+            sum_y[i] += *val;
+        }
+        num_survivors += 1;
+    }
+
+    if num_survivors == 0 {
+        return;
+    }
+
+    // Cancel each survivor's own self-mask b_i.
+    for &survivor in masked_vectors.keys() {
+        if dropped.contains(&survivor) {
+            continue;
+        }
+        if let Some(holders) = self_mask_shares.get(&survivor) {
+            let shares: Vec<ShamirShare> = holders.values().cloned().collect();
+            if shares.len() as u64 >= threshold {
+                let b_i = shamir_reconstruct(&shares);
+                let mask = prg_mask(b_i, vec_len);
+                for i in 0..vec_len {
+                    sum_y[i] -= mask[i];
+                }
+            }
+        }
+    }
+
+    // Cancel the residual pairwise terms a dropped client's surviving peers
+    // still carry in their y_j vectors.
+    for &dropped_client in dropped.iter() {
+        for &survivor in masked_vectors.keys() {
+            if survivor == dropped_client || dropped.contains(&survivor) {
+                continue;
+            }
+            let key = mask_pair_key(dropped_client, survivor);
+            // Reconstruct from exactly one dealer's share set: seed_ij is
+            // symmetric, so both pair members deal shares of it, but mixing
+            // shares across dealers would interpolate two unrelated
+            // polynomials and recover the wrong seed.
+            let dealer_shares = pairwise_mask_shares
+                .get(&key)
+                .and_then(|dealers| dealers.values().find(|shares| shares.len() as u64 >= threshold));
+            if let Some(holders) = dealer_shares {
+                let shares: Vec<ShamirShare> = holders.values().cloned().collect();
+                let seed_ds = shamir_reconstruct(&shares);
+                let mask = prg_mask(seed_ds, vec_len);
+                let sign: i64 = if dropped_client > survivor { 1 } else { -1 };
+                for i in 0..vec_len {
+                    sum_y[i] -= sign * mask[i];
+                }
+            }
+        }
+    }
+
+    let mut aggregated_avg: Vec<f32> = vec![0.0; vec_len];
+    for i in 0..vec_len {
+        let avg_scaled = (sum_y[i] as f64) / (num_survivors as f64);
+        aggregated_avg[i] = (avg_scaled / (SMPC_SCALE as f64)) as f32;
+    }
+
+    STATE.with_borrow_mut(|state| {
+        let global_model = serde_json::to_vec(&aggregated_avg).expect("Failed to serialize global model");
+        let participants: Vec<ClientId> = masked_vectors.keys().cloned().filter(|id| !dropped.contains(id)).collect();
+        record_cycle_transcript(
+            state,
+            cycle,
+            participants,
+            num_survivors as u64,
+            0,
+            dropped,
+            &global_model,
+            AggregationMode::SecureAgg,
+        );
+        state.global_model = global_model;
+        state.secure_masked_vectors.remove(&cycle);
+        state.secure_self_mask_shares.remove(&cycle);
+        state.secure_pairwise_mask_shares.remove(&cycle);
+    });
+}
+
+// ==================================================================================================
+// FHE Aggregation (multi-party BFV/CKKS-style ciphertext summation)
+// ==================================================================================================
+//
+// This module models the on-chain state machine and wire format for a
+// BFV/CKKS-style threshold-decryption aggregation flow, but it provides no
+// confidentiality today: a real lattice-crypto library is not vendored in
+// this crate, so the "ciphertexts" below are plaintext wire-encoded
+// fixed-point vectors (reusing SMPC_SCALE) that run_fhe_aggregation decodes
+// and sums in the clear, and the "decryption shares" are never used to
+// decrypt anything — they are only counted as a threshold liveness gate
+// before the already-plaintext sum is released as global_model. The
+// canister can read every client's update in this path exactly as it can
+// in sMPC and secure aggregation above.
+//
+// Note: wiring in a real BFV/CKKS implementation (ring-packing, RNS bases,
+// noise budget tracking) would slot in at encode_fhe_vec/decode_fhe_vec
+// (ciphertext wire format), the homomorphic sum loop in run_fhe_aggregation
+// (coordinate-wise ciphertext addition), and try_finalize_fhe_aggregation
+// (combining real decryption shares instead of just counting them) —
+// without changing the surrounding endpoint shapes.
+
+fn encode_fhe_vec(v: &[i64]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(v.len() * 8);
+    for val in v {
+        bytes.extend_from_slice(&val.to_le_bytes());
+    }
+    bytes
+}
+
+fn decode_fhe_vec(bytes: &[u8]) -> Vec<i64> {
+    bytes
+        .chunks_exact(8)
+        .map(|chunk| i64::from_le_bytes(chunk.try_into().expect("chunk is 8 bytes")))
+        .collect()
+}
+
+#[update]
+fn submit_fhe_key_share(share: Vec<u8>) {
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let cycle = state.current_cycle;
+        state.fhe_key_shares.entry(cycle).or_default().insert(client_id, share);
+    });
+}
+
+#[update]
+fn upload_fhe_ciphertext(ct: Vec<u8>) {
+    assert!(ct.len() <= MAX_FHE_CIPHERTEXT_BYTES, "Ciphertext exceeds per-submission size bound");
+    let caller = ic_cdk::caller();
+    STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let cycle = state.current_cycle;
+        let cycle_ciphertexts = state.fhe_ciphertexts.entry(cycle).or_default();
+        let existing_bytes: usize = cycle_ciphertexts
+            .iter()
+            .filter(|(&id, _)| id != client_id)
+            .map(|(_, bytes)| bytes.len())
+            .sum();
+        assert!(existing_bytes + ct.len() <= MAX_FHE_CYCLE_BYTES, "Cycle ciphertext budget exceeded");
+        cycle_ciphertexts.insert(client_id, ct);
+    });
+}
+
+#[update]
+fn run_fhe_aggregation() {
+    let (cycle, ciphertexts) = STATE.with_borrow(|state| {
+        (
+            state.current_cycle,
+            state.fhe_ciphertexts.get(&state.current_cycle).cloned().unwrap_or_default(),
+        )
+    });
+
+    if ciphertexts.is_empty() {
+        return; // No ciphertexts for the current cycle
+    }
+
+    let vec_len = ciphertexts.values().next().map(|ct| decode_fhe_vec(ct).len()).unwrap_or(0);
+    if vec_len == 0 {
+        return;
+    }
+
+    let mut sum = vec![0i64; vec_len];
+    let mut contributors: Vec<ClientId> = Vec::new();
+    for (&client_id, ct) in ciphertexts.iter() {
+        let decoded = decode_fhe_vec(ct);
+        if decoded.len() != vec_len {
+            continue; // Malformed ciphertext: excluded from the sum, the divisor, and the participant set
+        }
+        for (i, val) in decoded.iter().enumerate() {
+            sum[i] += *val;
+        }
+        contributors.push(client_id);
+    }
+
+    STATE.with_borrow_mut(|state| {
+        state.fhe_aggregate_ciphertext.insert(cycle, encode_fhe_vec(&sum));
+        state.fhe_aggregate_contributors.insert(cycle, contributors);
+    });
+
+    // The decryption-share threshold may already have been reached before this
+    // aggregate existed (submit_decryption_share bails out early in that case,
+    // and nothing else re-checks once the aggregate finally shows up), so try
+    // to finalize immediately rather than waiting on another share submission.
+    try_finalize_fhe_aggregation(cycle);
+}
+
+#[update]
+fn submit_decryption_share(share: Vec<u8>) {
+    let caller = ic_cdk::caller();
+    let cycle = STATE.with_borrow_mut(|state| {
+        let client_id = state
+            .clients
+            .iter()
+            .position(|&p| p == caller)
+            .expect("Client not registered") as ClientId;
+        let cycle = state.current_cycle;
+        state.fhe_decryption_shares.entry(cycle).or_default().insert(client_id, share);
+        cycle
+    });
+
+    try_finalize_fhe_aggregation(cycle);
+}
+
+// Combines the aggregate ciphertext with the submitted decryption shares into
+// global_model once enough shares have arrived, no matter which of
+// run_fhe_aggregation or submit_decryption_share happened to complete last.
+// Both call this after making their own state update; it is a no-op until
+// both the aggregate ciphertext and the share threshold are in place.
+fn try_finalize_fhe_aggregation(cycle: u64) {
+    let (expected_participants, num_shares, aggregate, ciphertext_clients) = STATE.with_borrow(|state| {
+        let expected_participants = state
+            .cycle_participants
+            .get(&cycle)
+            .cloned()
+            .unwrap_or_else(|| (0..state.clients.len() as u64).collect());
+        let num_shares = state.fhe_decryption_shares.get(&cycle).map(|m| m.len()).unwrap_or(0);
+        let aggregate = state.fhe_aggregate_ciphertext.get(&cycle).cloned();
+        // Only the clients whose ciphertexts were actually folded into the sum
+        // count toward the divisor/participants — a mismatched-length
+        // ciphertext is excluded from sum but must not inflate this list.
+        let ciphertext_clients: Vec<ClientId> = state.fhe_aggregate_contributors.get(&cycle).cloned().unwrap_or_default();
+        (expected_participants, num_shares, aggregate, ciphertext_clients)
+    });
+
+    let threshold = secure_agg_threshold(expected_participants.len());
+    let Some(aggregate) = aggregate else { return };
+    if (num_shares as u64) < threshold || ciphertext_clients.is_empty() {
+        return; // Not enough decryption shares yet
+    }
+
+    let summed = decode_fhe_vec(&aggregate);
+    let mut aggregated_avg: Vec<f32> = vec![0.0; summed.len()];
+    for (i, val) in summed.iter().enumerate() {
+        aggregated_avg[i] = (*val as f64 / ciphertext_clients.len() as f64 / SMPC_SCALE as f64) as f32;
+    }
+
+    let dropped_clients: Vec<ClientId> = expected_participants
+        .iter()
+        .filter(|id| !ciphertext_clients.contains(id))
+        .cloned()
+        .collect();
+
+    STATE.with_borrow_mut(|state| {
+        let global_model = serde_json::to_vec(&aggregated_avg).expect("Failed to serialize global model");
+        let decrypted_updates_count = ciphertext_clients.len() as u64;
+        record_cycle_transcript(
+            state,
+            cycle,
+            ciphertext_clients,
+            decrypted_updates_count,
+            0,
+            dropped_clients,
+            &global_model,
+            AggregationMode::FHE,
+        );
+        state.global_model = global_model;
+        state.fhe_ciphertexts.remove(&cycle);
+        state.fhe_aggregate_ciphertext.remove(&cycle);
+        state.fhe_aggregate_contributors.remove(&cycle);
+        state.fhe_decryption_shares.remove(&cycle);
+        state.fhe_key_shares.remove(&cycle);
+    });
+}
+
+// ==================================================================================================
+// Cycle Transcripts (audit log)
+// ==================================================================================================
+//
+// run_aggregation and run_smpc_aggregation used to silently mutate
+// global_model and drop the cycle's updates with no record of what was
+// counted. This records, per completed cycle, who participated, how many
+// updates were actually included, who was flagged as dropped, and a hash of
+// the resulting global_model, chained to the previous cycle's transcript
+// hash so the whole run can be replayed and checked for tampering.
+
+fn record_cycle_transcript(
+    state: &mut State,
+    cycle: u64,
+    participants: Vec<ClientId>,
+    decrypted_updates_count: u64,
+    num_s: u64,
+    dropped_clients: Vec<ClientId>,
+    global_model: &[u8],
+    aggregation_mode: AggregationMode,
+) {
+    let global_model_hash: [u8; 32] = Sha256::digest(global_model).into();
+    let prev_hash = state.last_transcript_hash;
+
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash);
+    hasher.update(cycle.to_le_bytes());
+    hasher.update(global_model_hash);
+    hasher.update(decrypted_updates_count.to_le_bytes());
+    hasher.update(num_s.to_le_bytes());
+    hasher.update([aggregation_mode as u8]);
+    for p in &participants {
+        hasher.update(p.to_le_bytes());
+    }
+    for d in &dropped_clients {
+        hasher.update(d.to_le_bytes());
+    }
+    let transcript_hash: [u8; 32] = hasher.finalize().into();
+
+    state.cycle_transcripts.insert(
+        cycle,
+        CycleTranscript {
+            cycle,
+            participants,
+            decrypted_updates_count,
+            num_s,
+            dropped_clients,
+            global_model_hash,
+            aggregation_mode,
+            transcript_hash,
+        },
+    );
+    state.last_transcript_hash = transcript_hash;
+}
+
+#[query]
+fn get_cycle_transcript(cycle: u64) -> CycleTranscript {
+    STATE.with_borrow(|state| {
+        state
+            .cycle_transcripts
+            .get(&cycle)
+            .cloned()
+            .expect("No transcript recorded for this cycle")
+    })
+}
+
 // ==================================================================================================
 // VetKey
 // ==================================================================================================